@@ -1,24 +1,30 @@
 use crate::lnd::{get_lnd_client, get_network, Creds, LndCfg};
 use crate::lndk_offers::{get_destination, validate_amount};
 use crate::{
-    lndkrpc, Bolt12InvoiceString, OfferError, OfferHandler, PayOfferParams, TLS_CERT_FILENAME,
-    TLS_KEY_FILENAME,
+    lndkrpc, Bolt12InvoiceString, OfferError, OfferHandler, PayOfferParams, Retry,
+    TLS_CERT_FILENAME, TLS_KEY_FILENAME,
 };
 use bitcoin::secp256k1::PublicKey;
 use lightning::blinded_path::payment::BlindedPaymentPath;
 use lightning::blinded_path::{Direction, IntroductionNode};
 use lightning::ln::channelmanager::PaymentId;
-use lightning::offers::invoice::Bolt12Invoice;
-use lightning::offers::offer::Offer;
+use lightning::offers::invoice::{Bolt12Invoice, Bolt12InvoiceFeatures};
+use lightning::offers::nonce::Nonce;
+use lightning::offers::offer::{Amount, Offer};
+use lightning::offers::refund::Refund;
+use lightning::offers::static_invoice::StaticInvoice;
 use lightning::sign::EntropySource;
 use lightning::util::ser::Writeable;
 use lndkrpc::offers_server::Offers;
 use lndkrpc::{
-    Bolt12InvoiceContents, DecodeInvoiceRequest, FeatureBit, GetInvoiceRequest, GetInvoiceResponse,
-    PayInvoiceRequest, PayInvoiceResponse, PayOfferRequest, PayOfferResponse, PaymentHash,
-    PaymentPaths,
+    Bolt12InvoiceContents, CreateRefundRequest, CreateRefundResponse, DecodeInvoiceRequest,
+    DecodeStaticInvoiceRequest, FeatureBit, GetInvoiceRequest, GetInvoiceResponse,
+    PayInvoiceRequest, PayInvoiceResponse, PayOfferRequest, PayOfferResponse, PayRefundRequest,
+    PayRefundResponse, PayStaticInvoiceRequest, PayStaticInvoiceResponse, PaymentHash,
+    PaymentPaths, StaticInvoiceContents,
 };
 use rcgen::{generate_simple_self_signed, CertifiedKey, Error as RcgenError};
+use std::collections::HashSet;
 use std::error::Error;
 use std::fmt::Display;
 use std::fs::{metadata, set_permissions, File};
@@ -87,7 +93,10 @@ impl Offers for LNDKServer {
                 "Internal error: Couldn't get destination from offer: {e:?}"
             ))
         })?;
-        let reply_path = None;
+        let nonce = Nonce::from_entropy_source(&self.offer_handler.messenger_utils);
+        let reply_path = Some(self.offer_handler.create_reply_path(nonce).map_err(|e| {
+            Status::internal(format!("Internal error: Couldn't create reply path: {e}"))
+        })?);
         let info = client
             .lightning()
             .get_info(GetInfoRequest {})
@@ -107,11 +116,14 @@ impl Offers for LNDKServer {
             destination,
             reply_path,
             response_invoice_timeout: inner_request.response_invoice_timeout,
+            retry_strategy: convert_retry_strategy(inner_request.retry_strategy.clone()),
+            reply_path_nonce: Some(nonce),
+            require_authenticated_reply_path: inner_request.require_authenticated_reply_path,
         };
 
         let payment = match self.offer_handler.pay_offer(cfg).await {
             Ok(payment) => {
-                log::info!("Payment succeeded.");
+                log::info!("Payment succeeded after {} attempt(s).", payment.attempts);
                 payment
             }
             Err(e) => match e {
@@ -121,12 +133,22 @@ impl Offers for LNDKServer {
                 OfferError::InvalidCurrency => {
                     return Err(Status::invalid_argument(format!("{e}")))
                 }
+                OfferError::PaymentFailed {
+                    attempts,
+                    ref last_failure_reason,
+                } => {
+                    return Err(Status::internal(format!(
+                        "Gave up after {attempts} attempt(s), last failure: {last_failure_reason}"
+                    )))
+                }
                 _ => return Err(Status::internal(format!("Internal error: {e}"))),
             },
         };
 
         let reply = PayOfferResponse {
             payment_preimage: payment.payment_preimage,
+            attempts: payment.attempts,
+            last_failure_reason: payment.last_failure_reason.unwrap_or_default(),
         };
 
         Ok(Response::new(reply))
@@ -173,7 +195,10 @@ impl Offers for LNDKServer {
         let destination = get_destination(&offer)
             .await
             .map_err(|e| Status::unavailable(format!("Couldn't find destination: {e}")))?;
-        let reply_path = None;
+        let nonce = Nonce::from_entropy_source(&self.offer_handler.messenger_utils);
+        let reply_path = Some(self.offer_handler.create_reply_path(nonce).map_err(|e| {
+            Status::internal(format!("Internal error: Couldn't create reply path: {e}"))
+        })?);
 
         let info = client
             .lightning()
@@ -194,6 +219,9 @@ impl Offers for LNDKServer {
             destination,
             reply_path,
             response_invoice_timeout: inner_request.response_invoice_timeout,
+            retry_strategy: Retry::Attempts(1),
+            reply_path_nonce: Some(nonce),
+            require_authenticated_reply_path: inner_request.require_authenticated_reply_path,
         };
 
         let (invoice, _, payment_id) = match self.offer_handler.get_invoice(cfg).await {
@@ -256,20 +284,203 @@ impl Offers for LNDKServer {
             Err(e) => return Err(Status::invalid_argument(e.to_string())),
         };
         let payment_id = PaymentId(self.offer_handler.messenger_utils.get_secure_random_bytes());
-        let invoice = match self
+        let retry_strategy = convert_retry_strategy(inner_request.retry_strategy.clone());
+        let payment = match self
             .offer_handler
-            .pay_invoice(client, amount, &invoice, payment_id)
+            .pay_invoice(client, amount, &invoice, payment_id, retry_strategy)
             .await
         {
-            Ok(invoice) => {
-                log::info!("Invoice paid.");
-                invoice
+            Ok(payment) => {
+                log::info!("Invoice paid after {} attempt(s).", payment.attempts);
+                payment
+            }
+            Err(OfferError::PaymentFailed {
+                attempts,
+                last_failure_reason,
+            }) => {
+                return Err(Status::internal(format!(
+                    "Gave up after {attempts} attempt(s), last failure: {last_failure_reason}"
+                )))
             }
             Err(e) => return Err(Status::internal(format!("Error paying invoice: {e}"))),
         };
 
         let reply = PayInvoiceResponse {
-            payment_preimage: invoice.payment_preimage,
+            payment_preimage: payment.payment_preimage,
+            attempts: payment.attempts,
+            last_failure_reason: payment.last_failure_reason.unwrap_or_default(),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn create_refund(
+        &self,
+        request: Request<CreateRefundRequest>,
+    ) -> Result<Response<CreateRefundResponse>, Status> {
+        log::info!("Received a request: {:?}", request.get_ref());
+
+        let inner_request = request.get_ref();
+        let payer_note = if inner_request.payer_note.is_empty() {
+            None
+        } else {
+            Some(inner_request.payer_note.clone())
+        };
+
+        let refund = self
+            .offer_handler
+            .create_refund(
+                inner_request.amount,
+                inner_request.description.clone(),
+                inner_request.absolute_expiry,
+                payer_note,
+            )
+            .await
+            .map_err(|e| match e {
+                OfferError::InvalidAmount(e) => Status::invalid_argument(e.to_string()),
+                OfferError::InvalidCurrency => Status::invalid_argument(format!("{e}")),
+                _ => Status::internal(format!("Internal error: {e}")),
+            })?;
+
+        let reply = CreateRefundResponse {
+            refund: refund.to_string(),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn pay_refund(
+        &self,
+        request: Request<PayRefundRequest>,
+    ) -> Result<Response<PayRefundResponse>, Status> {
+        log::info!("Received a request: {:?}", request.get_ref());
+
+        let metadata = request.metadata();
+        let macaroon = check_auth_metadata(metadata)?;
+        let creds = Creds::String {
+            cert: self.lnd_cert.clone(),
+            macaroon,
+        };
+        let lnd_cfg = LndCfg::new(self.address.clone(), creds);
+        let client = get_lnd_client(lnd_cfg)
+            .map_err(|e| Status::unavailable(format!("Couldn't connect to lnd: {e}")))?;
+
+        let inner_request = request.get_ref();
+        let refund = Refund::from_str(&inner_request.refund).map_err(|e| {
+            Status::invalid_argument(format!(
+                "The provided refund was invalid. Please provide a valid refund in bech32 format,
+                i.e. starting with 'lnr'. Error: {e:?}"
+            ))
+        })?;
+
+        let nonce = Nonce::from_entropy_source(&self.offer_handler.messenger_utils);
+        let reply_path = self.offer_handler.create_reply_path(nonce).map_err(|e| {
+            Status::internal(format!("Internal error: Couldn't create reply path: {e}"))
+        })?;
+
+        let (payment, invoice) = match self
+            .offer_handler
+            .pay_refund(
+                client,
+                &refund,
+                inner_request.response_invoice_timeout,
+                reply_path,
+                nonce,
+                inner_request.require_authenticated_reply_path,
+            )
+            .await
+        {
+            Ok(result) => {
+                log::info!("Refund paid.");
+                result
+            }
+            Err(e) => match e {
+                OfferError::InvalidAmount(e) => {
+                    return Err(Status::invalid_argument(e.to_string()))
+                }
+                OfferError::InvalidCurrency => {
+                    return Err(Status::invalid_argument(format!("{e}")))
+                }
+                _ => return Err(Status::internal(format!("Internal error: {e}"))),
+            },
+        };
+
+        let reply = PayRefundResponse {
+            payment_preimage: payment.payment_preimage,
+            invoice_contents: Some(generate_bolt12_invoice_contents(&invoice)),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn decode_static_invoice(
+        &self,
+        request: Request<DecodeStaticInvoiceRequest>,
+    ) -> Result<Response<StaticInvoiceContents>, Status> {
+        log::info!("Received a request: {:?}", request.get_ref());
+
+        let invoice_string: Bolt12InvoiceString = request.get_ref().invoice.clone().into();
+        let invoice = StaticInvoice::try_from(invoice_string)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        let reply: StaticInvoiceContents = generate_static_invoice_contents(&invoice);
+        Ok(Response::new(reply))
+    }
+
+    async fn pay_static_invoice(
+        &self,
+        request: Request<PayStaticInvoiceRequest>,
+    ) -> Result<Response<PayStaticInvoiceResponse>, Status> {
+        log::info!("Received a request: {:?}", request.get_ref());
+
+        let metadata = request.metadata();
+        let macaroon = check_auth_metadata(metadata)?;
+        let creds = Creds::String {
+            cert: self.lnd_cert.clone(),
+            macaroon,
+        };
+        let lnd_cfg = LndCfg::new(self.address.clone(), creds);
+        let mut client = get_lnd_client(lnd_cfg)
+            .map_err(|e| Status::unavailable(format!("Couldn't connect to lnd: {e}")))?;
+
+        let inner_request = request.get_ref();
+        let offer = Offer::from_str(&inner_request.offer).map_err(|e| {
+            Status::invalid_argument(format!(
+                "The provided offer was invalid. Please provide a valid offer in bech32 format,
+                i.e. starting with 'lno'. Error: {e:?}"
+            ))
+        })?;
+
+        let invoice_string: Bolt12InvoiceString = inner_request.static_invoice.clone().into();
+        let static_invoice = StaticInvoice::try_from(invoice_string).map_err(|e| {
+            Status::invalid_argument(format!(
+                "The provided static invoice was invalid. Please provide a valid static invoice
+                in hex format. Error: {e:?}"
+            ))
+        })?;
+
+        let info = client
+            .lightning()
+            .get_info(GetInfoRequest {})
+            .await
+            .map_err(|e| Status::unavailable(format!("Couldn't connect to lnd: {e}")))?
+            .into_inner();
+        let network = get_network(info)
+            .await
+            .map_err(|e| Status::internal(format!("{e:?}")))?;
+
+        let payment = self
+            .offer_handler
+            .pay_static_invoice(&offer, &static_invoice, inner_request.amount, network, client)
+            .await
+            .map_err(|e| match e {
+                OfferError::InvalidAmount(e) => Status::invalid_argument(e.to_string()),
+                OfferError::InvalidStaticInvoice(e) => Status::invalid_argument(e.to_string()),
+                _ => Status::internal(format!("Internal error: {e}")),
+            })?;
+
+        let reply = PayStaticInvoiceResponse {
+            payment_preimage: payment.payment_preimage,
         };
 
         Ok(Response::new(reply))
@@ -394,6 +605,37 @@ fn generate_bolt12_invoice_contents(invoice: &Bolt12Invoice) -> lndkrpc::Bolt12I
     }
 }
 
+fn generate_static_invoice_contents(invoice: &StaticInvoice) -> lndkrpc::StaticInvoiceContents {
+    StaticInvoiceContents {
+        chain: invoice.chain().to_string(),
+        amount_msats: resolve_static_invoice_amount_msats(invoice),
+        created_at: invoice.created_at().as_secs() as i64,
+        relative_expiry: invoice.relative_expiry().as_secs(),
+        node_id: Some(convert_public_key(&invoice.signing_pubkey())),
+        payment_paths: invoice
+            .payment_paths()
+            .iter()
+            .map(|path| PaymentPaths {
+                blinded_pay_info: Some(convert_blinded_pay_info(&path.payinfo)),
+                blinded_path: Some(convert_blinded_path(path)),
+            })
+            .collect(),
+        features: convert_invoice_features(invoice.invoice_features().clone()),
+    }
+}
+
+// resolve_static_invoice_amount_msats turns the offer-style amount a StaticInvoice carries into a
+// concrete msat value where we can. Unlike a Bolt12Invoice, a static invoice may be amountless
+// (like the offer it's derived from), or denominated in a fiat currency that pay_static_invoice
+// would resolve using the payer-supplied override; at decode time we have no such override, so we
+// can only report an amount here when the invoice already fixes it in msats.
+fn resolve_static_invoice_amount_msats(invoice: &StaticInvoice) -> Option<u64> {
+    match invoice.amount()? {
+        Amount::Bitcoin { amount_msats } => Some(amount_msats),
+        Amount::Currency { .. } => None,
+    }
+}
+
 fn encode_invoice_as_hex(invoice: &Bolt12Invoice) -> Result<String, Status> {
     let mut buffer = Vec::new();
     invoice
@@ -418,8 +660,72 @@ fn convert_public_key(native_pub_key: &PublicKey) -> lndkrpc::PublicKey {
     lndkrpc::PublicKey { key: pub_key_bytes }
 }
 
-fn convert_invoice_features(_features: impl std::fmt::Debug) -> Vec<i32> {
-    vec![FeatureBit::MppOpt as i32]
+// convert_retry_strategy turns the retry strategy set on a request into the Retry policy
+// OfferHandler understands. Requests that don't set one fall back to a single attempt, which
+// matches the pre-existing "fail fast" behavior of pay_offer/pay_invoice.
+fn convert_retry_strategy(retry_strategy: Option<lndkrpc::RetryStrategy>) -> Retry {
+    match retry_strategy.and_then(|strategy| strategy.strategy) {
+        Some(lndkrpc::retry_strategy::Strategy::MaxAttempts(attempts)) => {
+            Retry::Attempts(attempts)
+        }
+        Some(lndkrpc::retry_strategy::Strategy::TimeoutSeconds(timeout)) => {
+            Retry::Timeout(std::time::Duration::from_secs(timeout.into()))
+        }
+        None => Retry::Attempts(1),
+    }
+}
+
+// convert_invoice_features maps each even/odd feature bit pair in an invoice's feature bitvector
+// onto its named FeatureBit, preferring the "required" variant when both are somehow set. Bits we
+// don't have a name for are still surfaced, as their raw bit position, so callers don't lose
+// information about features we haven't caught up with yet.
+fn convert_invoice_features(features: Bolt12InvoiceFeatures) -> Vec<i32> {
+    let mut feature_bits = Vec::new();
+    let mut known_bits = HashSet::new();
+
+    let mut add_pair = |required_bit: i32, optional_bit: i32, requires: bool, supports: bool| {
+        known_bits.insert(required_bit as u32);
+        known_bits.insert(optional_bit as u32);
+        if requires {
+            feature_bits.push(required_bit);
+        } else if supports {
+            feature_bits.push(optional_bit);
+        }
+    };
+
+    add_pair(
+        FeatureBit::PaymentAddrReq as i32,
+        FeatureBit::PaymentAddrOpt as i32,
+        features.requires_payment_secret(),
+        features.supports_payment_secret(),
+    );
+    add_pair(
+        FeatureBit::MppReq as i32,
+        FeatureBit::MppOpt as i32,
+        features.requires_basic_mpp(),
+        features.supports_basic_mpp(),
+    );
+    add_pair(
+        FeatureBit::TlvOnionReq as i32,
+        FeatureBit::TlvOnionOpt as i32,
+        features.requires_variable_length_onion(),
+        features.supports_variable_length_onion(),
+    );
+
+    // Anything left over doesn't correspond to a feature we recognize by name; pass its raw bit
+    // position through so callers can still see that the payee advertised it.
+    for (byte_index, byte) in features.le_flags().iter().enumerate() {
+        for bit_in_byte in 0..8u32 {
+            if byte & (1 << bit_in_byte) != 0 {
+                let bit_position = byte_index as u32 * 8 + bit_in_byte;
+                if !known_bits.contains(&bit_position) {
+                    feature_bits.push(bit_position as i32);
+                }
+            }
+        }
+    }
+
+    feature_bits
 }
 
 fn convert_blinded_pay_info(
@@ -492,4 +798,46 @@ mod tests {
         assert!(tls_ips.is_some());
         assert!(tls_ips.as_ref().unwrap().len() == 2);
     }
+
+    #[test]
+    fn test_convert_invoice_features() {
+        let mut features = Bolt12InvoiceFeatures::empty();
+        features.set_basic_mpp_optional();
+        features.set_payment_secret_required();
+        // A bit we don't map by name, to exercise the unknown-bit passthrough.
+        features.set_optional_feature_bit(101).unwrap();
+
+        let mut feature_bits = convert_invoice_features(features);
+        feature_bits.sort_unstable();
+
+        let mut expected = vec![
+            FeatureBit::MppOpt as i32,
+            FeatureBit::PaymentAddrReq as i32,
+            101,
+        ];
+        expected.sort_unstable();
+
+        assert_eq!(feature_bits, expected);
+    }
+
+    #[test]
+    fn test_convert_retry_strategy() {
+        assert!(matches!(convert_retry_strategy(None), Retry::Attempts(1)));
+
+        let max_attempts = lndkrpc::RetryStrategy {
+            strategy: Some(lndkrpc::retry_strategy::Strategy::MaxAttempts(5)),
+        };
+        assert!(matches!(
+            convert_retry_strategy(Some(max_attempts)),
+            Retry::Attempts(5)
+        ));
+
+        let timeout_seconds = lndkrpc::RetryStrategy {
+            strategy: Some(lndkrpc::retry_strategy::Strategy::TimeoutSeconds(30)),
+        };
+        assert!(matches!(
+            convert_retry_strategy(Some(timeout_seconds)),
+            Retry::Timeout(timeout) if timeout == std::time::Duration::from_secs(30)
+        ));
+    }
 }